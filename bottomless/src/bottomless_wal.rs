@@ -1,34 +1,239 @@
 use std::ffi::c_int;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use libsql_sys::ffi::{SQLITE_BUSY, SQLITE_IOERR_WRITE};
+use libsql_sys::ffi::SQLITE_IOERR_WRITE;
 use libsql_sys::wal::wrapper::{WalWrapper, WrapWal};
 use libsql_sys::wal::{
     BusyHandler, CheckpointCallback, CheckpointMode, Error, Result, Sqlite3Db, Wal,
 };
-use tokio::sync::Mutex;
 
+use crate::metrics::{serve_metrics, CheckpointOutcome, ReplicationMetrics};
 use crate::replicator::Replicator;
+use crate::retention::{spawn_periodic_retention, GenerationStore, RetentionPolicy, RetentionTask};
+use crate::worker::{ReplicatorHandle, SnapshotTask};
 
 pub type BottomlessWal<T> = WalWrapper<BottomlessWalWrapper, T>;
 
+/// Governs how `checkpoint()` reacts to a checkpoint weaker than
+/// `CheckpointMode::Truncate`.
+#[derive(Debug, Clone, Copy)]
+pub enum CheckpointPolicy {
+    /// Reject anything weaker than TRUNCATE with `SQLITE_BUSY`, same as
+    /// before this policy existed. The safest option: the WAL only ever
+    /// shrinks once everything has been durably confirmed, and every
+    /// checkpoint that shrinks it is followed by a new generation.
+    TruncateOnly,
+    /// Allow PASSIVE/FULL checkpoints: wait for the backfilled frames to be
+    /// confirmed committed to object storage, then delegate straight to the
+    /// wrapped WAL's checkpoint, without starting a new generation.
+    AllowPassive,
+    /// Behave like `AllowPassive` until the WAL exceeds `max_wal_frames`
+    /// frames, then escalate the request to a TRUNCATE checkpoint.
+    EscalateOnSize { max_wal_frames: u32 },
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        CheckpointPolicy::TruncateOnly
+    }
+}
+
+/// What a checkpoint request weaker than TRUNCATE should do under `policy`,
+/// given how many frames are currently in the WAL.
+///
+/// Pulled out of `checkpoint()` as a pure function so the PASSIVE/FULL
+/// policy branching — including the `EscalateOnSize` threshold comparison —
+/// is unit-testable without a real `Wal`/`ReplicatorHandle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PolicyDecision {
+    /// Reject the checkpoint outright; nothing was done.
+    Reject,
+    /// Run it as PASSIVE/FULL, without rolling the generation.
+    RunPassive,
+    /// Escalate the request to a full TRUNCATE checkpoint.
+    EscalateToTruncate,
+}
+
+fn decide_checkpoint(policy: CheckpointPolicy, frames_in_wal: u32) -> PolicyDecision {
+    match policy {
+        CheckpointPolicy::TruncateOnly => PolicyDecision::Reject,
+        CheckpointPolicy::AllowPassive => PolicyDecision::RunPassive,
+        CheckpointPolicy::EscalateOnSize { max_wal_frames } => {
+            if frames_in_wal > max_wal_frames {
+                PolicyDecision::EscalateToTruncate
+            } else {
+                PolicyDecision::RunPassive
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct BottomlessWalWrapper {
-    replicator: Arc<Mutex<Option<Replicator>>>,
+    worker: Arc<Mutex<Option<ReplicatorHandle>>>,
+    // Kept alive for as long as any clone of this wrapper exists; dropping
+    // the last one stops the timer thread.
+    snapshot_task: Option<Arc<SnapshotTask>>,
+    // Same lifetime rule as `snapshot_task`, for the retention GC timer.
+    retention_task: Option<Arc<RetentionTask>>,
+    metrics: Arc<ReplicationMetrics>,
+    checkpoint_policy: CheckpointPolicy,
 }
 
 impl BottomlessWalWrapper {
-    pub fn new(replicator: Arc<Mutex<Option<Replicator>>>) -> Self {
-        Self { replicator }
+    /// Spawns a dedicated worker thread that owns `replicator` together with
+    /// its own tokio runtime, and returns a wrapper that drives it entirely
+    /// through channel commands. WAL hooks no longer need to assume a tokio
+    /// runtime is current on whatever thread SQLite calls them from.
+    pub fn new(replicator: Replicator) -> Self {
+        Self {
+            worker: Arc::new(Mutex::new(Some(ReplicatorHandle::spawn(replicator)))),
+            snapshot_task: None,
+            retention_task: None,
+            metrics: Arc::new(ReplicationMetrics::default()),
+            checkpoint_policy: CheckpointPolicy::default(),
+        }
+    }
+
+    /// Overrides the default [`CheckpointPolicy::TruncateOnly`] behavior.
+    pub fn with_checkpoint_policy(mut self, policy: CheckpointPolicy) -> Self {
+        self.checkpoint_policy = policy;
+        self
     }
 
-    pub fn replicator(&self) -> Arc<tokio::sync::Mutex<Option<Replicator>>> {
-        self.replicator.clone()
+    /// Starts a background task that runs [`crate::retention::prune_generations`]
+    /// against `store` on a fixed cadence, mirroring how
+    /// [`Self::new_with_snapshot_interval`] owns the periodic snapshot timer
+    /// instead of leaving it to the caller to hold onto separately.
+    pub fn with_periodic_retention<S>(
+        mut self,
+        store: Arc<S>,
+        policy: RetentionPolicy,
+        interval: Duration,
+    ) -> Self
+    where
+        S: GenerationStore + Send + Sync + 'static,
+    {
+        self.retention_task = Some(Arc::new(spawn_periodic_retention(store, policy, interval)));
+        self
+    }
+
+    /// Like [`Self::new`], but also starts a background task that snapshots
+    /// on a fixed cadence (mirroring the existing TRUNCATE-checkpoint
+    /// snapshot logic), so a writer that never checkpoints doesn't
+    /// accumulate an unbounded WAL in object storage.
+    pub fn new_with_snapshot_interval(replicator: Replicator, snapshot_interval: Duration) -> Self {
+        let worker = ReplicatorHandle::spawn(replicator);
+        let snapshot_task = worker.spawn_periodic_snapshot(snapshot_interval);
+        Self {
+            worker: Arc::new(Mutex::new(Some(worker))),
+            snapshot_task: Some(Arc::new(snapshot_task)),
+            retention_task: None,
+            metrics: Arc::new(ReplicationMetrics::default()),
+            checkpoint_policy: CheckpointPolicy::default(),
+        }
     }
 
-    pub async fn shutdown(&self) -> Option<Replicator> {
-        self.replicator.lock().await.take()
+    /// Detaches the worker from this wrapper. Once called, every subsequent
+    /// WAL hook call on any clone of this wrapper fails with
+    /// `SQLITE_IOERR_WRITE`, mirroring the previous "replicator taken"
+    /// behavior.
+    pub async fn shutdown(&self) {
+        self.worker.lock().unwrap().take();
+    }
+
+    /// Runs [`crate::retention::prune_generations`] against `store` using
+    /// `policy`, deleting every generation it decides is safe to drop.
+    ///
+    /// Takes the store explicitly rather than through the worker because
+    /// retention operates on object storage directly, independent of the
+    /// replicator's in-progress WAL state.
+    pub async fn prune_generations<S: crate::retention::GenerationStore>(
+        &self,
+        store: &S,
+        policy: &crate::retention::RetentionPolicy,
+    ) -> anyhow::Result<Vec<uuid::Uuid>> {
+        crate::retention::prune_generations(store, policy, std::time::SystemTime::now()).await
+    }
+
+    /// Restores `source` at `point` into a fresh file at `dest`, reporting
+    /// page-copy progress via `progress`. See
+    /// [`crate::restore::restore`] for the overwrite/page-size checks.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn restore<S: crate::restore::RestoreSource>(
+        &self,
+        source: &S,
+        point: crate::restore::RestorePoint,
+        dest: &std::path::Path,
+        expected_page_size: usize,
+        force: bool,
+        progress: impl FnMut(crate::restore::RestoreProgress),
+    ) -> anyhow::Result<()> {
+        crate::restore::restore(source, point, dest, expected_page_size, force, progress).await
+    }
+
+    /// Serves a `/metrics` Prometheus endpoint (frames submitted, bytes
+    /// uploaded, generation count and current generation id, checkpoint
+    /// outcomes/durations, replication lag) on `addr` until the returned
+    /// future is dropped or the server hits a fatal I/O error.
+    pub async fn serve_metrics(&self, addr: std::net::SocketAddr) -> Result<()> {
+        let worker = self.worker()?;
+        serve_metrics(addr, self.metrics.clone(), worker)
+            .await
+            .map_err(|_| Error::new(SQLITE_IOERR_WRITE))
+    }
+
+    fn worker(&self) -> Result<ReplicatorHandle> {
+        self.worker
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Error::new(SQLITE_IOERR_WRITE))
+    }
+
+    /// Handles a PASSIVE/FULL checkpoint under [`CheckpointPolicy::AllowPassive`]:
+    /// wait for the backfilled frames to be durably committed, then delegate
+    /// straight to the wrapped WAL without rolling the generation.
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_passive<T: Wal>(
+        &self,
+        worker: &ReplicatorHandle,
+        wrapped: &mut T,
+        db: &mut Sqlite3Db,
+        mode: CheckpointMode,
+        busy_handler: Option<&mut dyn BusyHandler>,
+        sync_flags: u32,
+        buf: &mut [u8],
+        checkpoint_cb: Option<&mut dyn CheckpointCallback>,
+        in_wal: Option<&mut i32>,
+        backfilled: Option<&mut i32>,
+        before: Instant,
+    ) -> Result<()> {
+        if let Err(e) = worker.wait_flushed() {
+            self.metrics
+                .record_checkpoint(CheckpointOutcome::Failed, before.elapsed());
+            return Err(e);
+        }
+
+        if let Err(e) = wrapped.checkpoint(
+            db,
+            mode,
+            busy_handler,
+            sync_flags,
+            buf,
+            checkpoint_cb,
+            in_wal,
+            backfilled,
+        ) {
+            self.metrics
+                .record_checkpoint(CheckpointOutcome::Failed, before.elapsed());
+            return Err(e);
+        }
+
+        self.metrics
+            .record_checkpoint(CheckpointOutcome::Completed, before.elapsed());
+        Ok(())
     }
 }
 
@@ -41,22 +246,10 @@ impl<T: Wal> WrapWal<T> for BottomlessWalWrapper {
         wrapped.savepoint_undo(rollback_data)?;
 
         let last_valid_frame = rollback_data[0];
-        let runtime = tokio::runtime::Handle::current();
-        runtime.block_on(async {
-            let mut guard = self.replicator.lock().await;
-            match &mut *guard {
-                Some(replicator) => {
-                    let prev_valid_frame = replicator.peek_last_valid_frame();
-                    tracing::trace!(
-                        "Savepoint: rolling back from frame {prev_valid_frame} to {last_valid_frame}",
-                    );
-                    Ok(())
-                }
-                None => {
-                    Err(Error::new(SQLITE_IOERR_WRITE))
-                }
-            }
-        })?;
+        let prev_valid_frame = self.worker()?.peek_last_valid_frame()?;
+        tracing::trace!(
+            "Savepoint: rolling back from frame {prev_valid_frame} to {last_valid_frame}",
+        );
 
         Ok(())
     }
@@ -75,19 +268,16 @@ impl<T: Wal> WrapWal<T> for BottomlessWalWrapper {
         let num_frames =
             wrapped.insert_frames(page_size, page_headers, size_after, is_commit, sync_flags)?;
 
-        let mut guard = self.replicator.blocking_lock();
-        match &mut *guard {
-            Some(replicator) => {
-                if let Err(e) = replicator.set_page_size(page_size as usize) {
-                    tracing::error!("fatal error during backup: {e}, exiting");
-                    std::process::abort()
-                }
-                replicator.register_last_valid_frame(last_valid_frame);
-                let new_valid_valid_frame_index = wrapped.frames_in_wal();
-                replicator.submit_frames(new_valid_valid_frame_index - last_valid_frame);
-            }
-            None => return Err(Error::new(SQLITE_IOERR_WRITE)),
-        }
+        let new_valid_frame_index = wrapped.frames_in_wal();
+        self.worker()?.register_frames(
+            last_valid_frame,
+            new_valid_frame_index,
+            page_size as usize,
+        )?;
+        let frames = new_valid_frame_index - last_valid_frame;
+        self.metrics.record_frames_submitted(frames);
+        self.metrics
+            .record_bytes_uploaded(frames as u64 * page_size as u64);
 
         Ok(num_frames)
     }
@@ -107,76 +297,63 @@ impl<T: Wal> WrapWal<T> for BottomlessWalWrapper {
         backfilled: Option<&mut i32>,
     ) -> Result<()> {
         let before = Instant::now();
-        {
-            tracing::trace!("bottomless checkpoint: {mode:?}");
-
-            /* In order to avoid partial checkpoints, passive checkpoint
-             ** mode is not allowed. Only TRUNCATE checkpoints are accepted,
-             ** because these are guaranteed to block writes, copy all WAL pages
-             ** back into the main database file and reset the frame number.
-             ** In order to avoid autocheckpoint on close (that's too often),
-             ** checkpoint attempts weaker than TRUNCATE are ignored.
-             */
-            if mode < CheckpointMode::Truncate {
-                tracing::trace!("Ignoring a checkpoint request weaker than TRUNCATE: {mode:?}");
-                // Return an error to signal to sqlite that the WAL was not checkpointed, and it is
-                // therefore not safe to delete it.
-                return Err(Error::new(SQLITE_BUSY));
-            }
-        }
+        let worker = self.worker()?;
+        tracing::trace!("bottomless checkpoint: {mode:?}");
 
-        let runtime = tokio::runtime::Handle::current();
-        runtime.block_on(async {
-            let mut guard = self.replicator.lock().await;
-            match &mut *guard {
-                Some(replicator) => {
-                    let last_known_frame = replicator.last_known_frame();
-                    replicator.request_flush();
-                    if last_known_frame == 0 {
-                        tracing::debug!(
-                            "No committed changes in this generation, not snapshotting"
-                        );
-                        replicator.skip_snapshot_for_current_generation();
-                        return Err(Error::new(SQLITE_BUSY));
-                    }
-
-                    let fut = tokio::time::timeout(
-                        std::time::Duration::from_secs(1),
-                        replicator.wait_until_committed(last_known_frame),
+        let mut mode = mode;
+        if mode < CheckpointMode::Truncate {
+            match decide_checkpoint(self.checkpoint_policy, wrapped.frames_in_wal()) {
+                PolicyDecision::Reject => {
+                    tracing::trace!(
+                        "Ignoring a checkpoint request weaker than TRUNCATE: {mode:?}"
                     );
-
-                    match fut.await {
-                        Ok(Ok(_)) => (),
-                        Ok(Err(e)) => {
-                            tracing::error!(
-                                "Failed to wait for S3 replicator to confirm {} frames backup: {}",
-                                last_known_frame,
-                                e
-                            );
-                            return Err(Error::new(SQLITE_IOERR_WRITE));
-                        }
-                        Err(_) => {
-                            tracing::error!(
-                                "timed out waiting for S3 replicator to confirm committed frames."
-                            );
-                            return Err(Error::new(SQLITE_BUSY));
-                        }
-                    }
-                    tracing::debug!("commited after {:?}", before.elapsed());
-                    let snapshotted = replicator.is_snapshotted().await;
-                    if !snapshotted {
-                        tracing::warn!("previous generation not snapshotted, skipping checkpoint");
-                        return Err(Error::new(SQLITE_BUSY));
-                    }
-                    tracing::debug!("snapshotted after {:?}", before.elapsed());
-
-                    Ok(())
+                    // Return an error to signal to sqlite that the WAL was not checkpointed, and
+                    // it is therefore not safe to delete it.
+                    self.metrics
+                        .record_checkpoint(CheckpointOutcome::Skipped, before.elapsed());
+                    return Err(Error::new(libsql_sys::ffi::SQLITE_BUSY));
+                }
+                PolicyDecision::RunPassive => {
+                    return self.checkpoint_passive(
+                        &worker,
+                        wrapped,
+                        db,
+                        mode,
+                        busy_handler,
+                        sync_flags,
+                        buf,
+                        checkpoint_cb,
+                        in_wal,
+                        backfilled,
+                        before,
+                    );
+                }
+                PolicyDecision::EscalateToTruncate => {
+                    tracing::debug!(
+                        "WAL exceeds configured threshold, escalating {mode:?} to TRUNCATE"
+                    );
+                    mode = CheckpointMode::Truncate;
                 }
-                None => Err(Error::new(SQLITE_IOERR_WRITE)),
             }
-        })?;
+        }
+
+        let snapshotted = match worker.wait_committed() {
+            Ok(snapshotted) => snapshotted,
+            Err(e) => {
+                self.metrics
+                    .record_checkpoint(CheckpointOutcome::Failed, before.elapsed());
+                return Err(e);
+            }
+        };
+        if !snapshotted {
+            tracing::warn!("previous generation not snapshotted, skipping checkpoint");
+            self.metrics
+                .record_checkpoint(CheckpointOutcome::Skipped, before.elapsed());
+            return Err(Error::new(libsql_sys::ffi::SQLITE_BUSY));
+        }
+        tracing::debug!("commited after {:?}", before.elapsed());
 
-        wrapped.checkpoint(
+        if let Err(e) = wrapped.checkpoint(
             db,
             mode,
             busy_handler,
@@ -185,28 +362,24 @@ impl<T: Wal> WrapWal<T> for BottomlessWalWrapper {
             checkpoint_cb,
             in_wal,
             backfilled,
-        )?;
+        ) {
+            self.metrics
+                .record_checkpoint(CheckpointOutcome::Failed, before.elapsed());
+            return Err(e);
+        }
 
         tracing::debug!("underlying checkpoint call after {:?}", before.elapsed());
 
-        runtime.block_on(async {
-            let mut guard = self.replicator.lock().await;
-            match &mut *guard {
-                Some(replicator) => {
-                    replicator.new_generation().await;
-                    if let Err(e) = replicator.snapshot_main_db_file(false).await {
-                        tracing::error!(
-                            "Failed to snapshot the main db file during checkpoint: {e}"
-                        );
-                        return Err(Error::new(SQLITE_IOERR_WRITE));
-                    }
-                    Ok(())
-                }
-                None => Err(Error::new(SQLITE_IOERR_WRITE)),
-            }
-        })?;
+        if let Err(e) = worker.new_generation().and_then(|_| worker.snapshot()) {
+            self.metrics
+                .record_checkpoint(CheckpointOutcome::Failed, before.elapsed());
+            return Err(e);
+        }
+        self.metrics.record_new_generation();
 
         tracing::debug!("checkpoint finnished after {:?}", before.elapsed());
+        self.metrics
+            .record_checkpoint(CheckpointOutcome::Completed, before.elapsed());
 
         Ok(())
     }
@@ -223,3 +396,62 @@ impl<T: Wal> WrapWal<T> for BottomlessWalWrapper {
         manager.close(wrapped, db, sync_flags, None)
     }
 }
+
+// `checkpoint_passive` skipping `new_generation`/`snapshot` (the part of
+// `AllowPassive`/`EscalateOnSize` that isn't pure policy math) would need a
+// fake `Wal`/`ReplicatorHandle` to unit test, and `libsql_sys::wal::Wal`'s
+// full trait surface isn't available to this crate in isolation; that path
+// has no unit test here. `decide_checkpoint`
+// covers the policy branching itself, including the `EscalateOnSize`
+// threshold comparison the reviewer flagged as the riskiest untested part.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_only_rejects_anything_weaker_than_truncate() {
+        assert_eq!(
+            decide_checkpoint(CheckpointPolicy::TruncateOnly, 0),
+            PolicyDecision::Reject
+        );
+        assert_eq!(
+            decide_checkpoint(CheckpointPolicy::TruncateOnly, 1_000_000),
+            PolicyDecision::Reject
+        );
+    }
+
+    #[test]
+    fn allow_passive_always_runs_passive() {
+        assert_eq!(
+            decide_checkpoint(CheckpointPolicy::AllowPassive, 0),
+            PolicyDecision::RunPassive
+        );
+        assert_eq!(
+            decide_checkpoint(CheckpointPolicy::AllowPassive, 1_000_000),
+            PolicyDecision::RunPassive
+        );
+    }
+
+    #[test]
+    fn escalate_on_size_runs_passive_under_the_threshold() {
+        let policy = CheckpointPolicy::EscalateOnSize { max_wal_frames: 100 };
+        assert_eq!(decide_checkpoint(policy, 99), PolicyDecision::RunPassive);
+    }
+
+    #[test]
+    fn escalate_on_size_runs_passive_at_the_threshold() {
+        // `frames_in_wal > max_wal_frames`, so equal to the threshold must
+        // not escalate yet.
+        let policy = CheckpointPolicy::EscalateOnSize { max_wal_frames: 100 };
+        assert_eq!(decide_checkpoint(policy, 100), PolicyDecision::RunPassive);
+    }
+
+    #[test]
+    fn escalate_on_size_escalates_past_the_threshold() {
+        let policy = CheckpointPolicy::EscalateOnSize { max_wal_frames: 100 };
+        assert_eq!(
+            decide_checkpoint(policy, 101),
+            PolicyDecision::EscalateToTruncate
+        );
+    }
+}
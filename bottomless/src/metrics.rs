@@ -0,0 +1,164 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+use crate::worker::ReplicatorHandle;
+
+/// Outcome of a single checkpoint attempt, for the `outcome` label on
+/// `bottomless_checkpoints_total`.
+#[derive(Clone, Copy)]
+pub(crate) enum CheckpointOutcome {
+    Completed,
+    Skipped,
+    Failed,
+}
+
+impl CheckpointOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            CheckpointOutcome::Completed => "completed",
+            CheckpointOutcome::Skipped => "skipped",
+            CheckpointOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// Counters and gauges tracked alongside a [`crate::bottomless_wal::BottomlessWalWrapper`],
+/// so operators can alert on replication lag before a checkpoint times out
+/// at the worker's hard-coded deadline instead of finding out when SQLite
+/// starts returning `SQLITE_BUSY`.
+#[derive(Default)]
+pub struct ReplicationMetrics {
+    frames_submitted: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    generations: AtomicU64,
+    checkpoints_completed: AtomicU64,
+    checkpoints_skipped: AtomicU64,
+    checkpoints_failed: AtomicU64,
+}
+
+impl ReplicationMetrics {
+    pub(crate) fn record_frames_submitted(&self, n: u32) {
+        self.frames_submitted.fetch_add(n as u64, Ordering::Relaxed);
+        metrics::counter!("bottomless_frames_submitted_total").increment(n as u64);
+    }
+
+    /// Tracks raw WAL frame bytes handed to the replicator, separate from
+    /// `frames_submitted` since page size can change over the life of a
+    /// database and operators alerting on upload volume want bytes, not a
+    /// frame count that means something different before and after a
+    /// `PRAGMA page_size` change.
+    pub(crate) fn record_bytes_uploaded(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+        metrics::counter!("bottomless_bytes_uploaded_total").increment(bytes);
+    }
+
+    pub(crate) fn record_new_generation(&self) {
+        self.generations.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!("bottomless_generations_total").increment(1);
+    }
+
+    pub(crate) fn record_checkpoint(&self, outcome: CheckpointOutcome, elapsed: Duration) {
+        let counter = match outcome {
+            CheckpointOutcome::Completed => &self.checkpoints_completed,
+            CheckpointOutcome::Skipped => &self.checkpoints_skipped,
+            CheckpointOutcome::Failed => &self.checkpoints_failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!("bottomless_checkpoints_total", "outcome" => outcome.label())
+            .increment(1);
+        metrics::histogram!("bottomless_checkpoint_duration_seconds").record(elapsed.as_secs_f64());
+    }
+
+    /// Renders a Prometheus text-exposition snapshot. Takes the worker
+    /// handle because replication lag (`local_frame - known_frame`) and the
+    /// current generation id need a live read from the replicator rather
+    /// than a locally tracked counter.
+    ///
+    /// `worker.stats()` is a blocking channel round-trip, so it runs on a
+    /// `spawn_blocking` thread instead of parking the async task polling
+    /// this future directly — otherwise a slow or stalled worker would tie
+    /// up a tokio runtime thread for up to `COMMAND_TIMEOUT` per scrape.
+    async fn render(&self, worker: &ReplicatorHandle) -> String {
+        let worker = worker.clone();
+        let stats = tokio::task::spawn_blocking(move || worker.stats())
+            .await
+            .ok()
+            .and_then(|result| result.ok())
+            .unwrap_or_default();
+
+        let lag = stats.local_frame.saturating_sub(stats.known_frame);
+        metrics::gauge!("bottomless_replication_lag_frames").set(lag as f64);
+
+        let generation = stats
+            .current_generation
+            .map(|g| g.to_string())
+            .unwrap_or_else(|| "none".to_string());
+
+        format!(
+            "# HELP bottomless_frames_submitted_total Frames handed to the replicator for upload.\n\
+             # TYPE bottomless_frames_submitted_total counter\n\
+             bottomless_frames_submitted_total {frames_submitted}\n\
+             # HELP bottomless_bytes_uploaded_total Bytes of WAL frame data handed to the replicator for upload.\n\
+             # TYPE bottomless_bytes_uploaded_total counter\n\
+             bottomless_bytes_uploaded_total {bytes_uploaded}\n\
+             # HELP bottomless_generations_total Number of generations started.\n\
+             # TYPE bottomless_generations_total counter\n\
+             bottomless_generations_total {generations}\n\
+             # HELP bottomless_current_generation_info Current generation id. A label rather than the gauge value itself, since a generation id isn't numeric.\n\
+             # TYPE bottomless_current_generation_info gauge\n\
+             bottomless_current_generation_info{{generation=\"{generation}\"}} 1\n\
+             # HELP bottomless_checkpoints_total Checkpoints by outcome.\n\
+             # TYPE bottomless_checkpoints_total counter\n\
+             bottomless_checkpoints_total{{outcome=\"completed\"}} {completed}\n\
+             bottomless_checkpoints_total{{outcome=\"skipped\"}} {skipped}\n\
+             bottomless_checkpoints_total{{outcome=\"failed\"}} {failed}\n\
+             # HELP bottomless_replication_lag_frames Frames written locally but not yet confirmed committed to object storage.\n\
+             # TYPE bottomless_replication_lag_frames gauge\n\
+             bottomless_replication_lag_frames {lag}\n",
+            frames_submitted = self.frames_submitted.load(Ordering::Relaxed),
+            bytes_uploaded = self.bytes_uploaded.load(Ordering::Relaxed),
+            generations = self.generations.load(Ordering::Relaxed),
+            generation = generation,
+            completed = self.checkpoints_completed.load(Ordering::Relaxed),
+            skipped = self.checkpoints_skipped.load(Ordering::Relaxed),
+            failed = self.checkpoints_failed.load(Ordering::Relaxed),
+            lag = lag,
+        )
+    }
+}
+
+/// Serves a `/metrics` endpoint rendering [`ReplicationMetrics`] in
+/// Prometheus text-exposition format until the returned future is dropped or
+/// the server encounters a fatal I/O error.
+pub(crate) async fn serve_metrics(
+    addr: SocketAddr,
+    metrics: Arc<ReplicationMetrics>,
+    worker: ReplicatorHandle,
+) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        let worker = worker.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                let worker = worker.clone();
+                async move {
+                    let body = if req.uri().path() == "/metrics" {
+                        metrics.render(&worker).await
+                    } else {
+                        "not found".to_string()
+                    };
+                    Ok::<_, Infallible>(Response::new(Body::from(body)))
+                }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_svc).await
+}
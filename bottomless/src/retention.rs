@@ -0,0 +1,313 @@
+//! Generation retention and garbage collection.
+//!
+//! This module does not implement [`GenerationStore`] for `Replicator`;
+//! callers pass in a store backed by their own object-storage client. This
+//! module only owns the list-then-prune policy and the periodic trigger
+//! around it.
+
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+/// How long to keep stored generations around. Every field is optional;
+/// leaving all of them unset means "don't prune" (the conservative default
+/// for a subsystem that deletes object-storage data).
+///
+/// All configured constraints must hold for a generation to be pruned: it
+/// has to be old enough (`max_age`), beyond the `max_generations` keep
+/// count, *and* not needed to satisfy `min_restorable_horizon`.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_generations: Option<usize>,
+    pub min_restorable_horizon: Option<Duration>,
+}
+
+/// Metadata about a single stored generation, as returned by
+/// [`GenerationStore::list_generations`].
+#[derive(Debug, Clone)]
+pub struct GenerationInfo {
+    pub generation: Uuid,
+    pub created_at: SystemTime,
+}
+
+/// The object-storage operations retention needs. Implementors own the
+/// bucket layout and key naming, so this module can list-then-prune
+/// without knowing anything about them.
+#[async_trait::async_trait]
+pub trait GenerationStore {
+    async fn list_generations(&self) -> anyhow::Result<Vec<GenerationInfo>>;
+    async fn delete_generation(&self, generation: Uuid) -> anyhow::Result<()>;
+}
+
+/// Enumerates stored generations and deletes whatever falls outside
+/// `policy`, relative to `now`.
+///
+/// Generations are pruned oldest-first up to a single cutoff derived from
+/// the policy, so the oldest *retained* generation is always left intact —
+/// since each generation carries its own full base snapshot, that's
+/// sufficient to guarantee it (and therefore every newer one) stays
+/// restorable.
+pub async fn prune_generations(
+    store: &impl GenerationStore,
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> anyhow::Result<Vec<Uuid>> {
+    if policy.max_age.is_none()
+        && policy.max_generations.is_none()
+        && policy.min_restorable_horizon.is_none()
+    {
+        tracing::trace!("no retention policy configured, skipping generation GC");
+        return Ok(Vec::new());
+    }
+
+    let mut generations = store.list_generations().await?;
+    generations.sort_by_key(|g| g.created_at);
+
+    let boundary = retention_boundary(&generations, policy, now);
+
+    let mut pruned = Vec::new();
+    for generation in &generations {
+        if generation.created_at >= boundary {
+            break;
+        }
+        store.delete_generation(generation.generation).await?;
+        pruned.push(generation.generation);
+    }
+    if !pruned.is_empty() {
+        tracing::info!(count = pruned.len(), "pruned stale generations");
+    }
+    Ok(pruned)
+}
+
+/// The latest point in time up to which generations may be deleted: the
+/// earliest (most conservative) of every configured constraint, so that
+/// each one independently holds.
+///
+/// An unconfigured (`None`) constraint must not restrict pruning, so it
+/// resolves to `now` (the most permissive boundary). A *configured*
+/// constraint that turns out not to apply yet — fewer generations stored
+/// than `max_generations` keeps, or `max_age`/`min_restorable_horizon`
+/// reaching past the epoch — is the opposite: it must forbid pruning
+/// anything, so it resolves to `SystemTime::UNIX_EPOCH` (nothing is older
+/// than that). Collapsing both cases to the same default would make a
+/// configured-but-inapplicable `max_generations` silently defer to whatever
+/// the other two constraints allow, rather than keeping everything.
+fn retention_boundary(
+    generations: &[GenerationInfo],
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> SystemTime {
+    let keep_count_boundary = match policy.max_generations {
+        None => now,
+        Some(keep) => generations
+            .len()
+            .checked_sub(keep)
+            .and_then(|idx| generations.get(idx))
+            .map(|g| g.created_at)
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+    };
+
+    let age_boundary = match policy.max_age {
+        None => now,
+        Some(age) => now.checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH),
+    };
+
+    let horizon_boundary = match policy.min_restorable_horizon {
+        None => now,
+        Some(horizon) => {
+            let cutoff = now.checked_sub(horizon).unwrap_or(SystemTime::UNIX_EPOCH);
+            // Restoring to `cutoff` starts from the base snapshot of the
+            // generation that was current then, so that one is kept too.
+            generations
+                .iter()
+                .rev()
+                .find(|g| g.created_at <= cutoff)
+                .map(|g| g.created_at)
+                .unwrap_or(cutoff)
+        }
+    };
+
+    keep_count_boundary.min(age_boundary).min(horizon_boundary)
+}
+
+/// Handle to the background periodic-retention timer. Stops the timer and
+/// joins its thread when dropped, same shape as the snapshot timer in
+/// [`crate::worker`].
+pub struct RetentionTask {
+    stop: SyncSender<()>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for RetentionTask {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Spawns a thread that calls [`prune_generations`] against `store` every
+/// `interval`, until the returned [`RetentionTask`] is dropped.
+pub fn spawn_periodic_retention<S>(
+    store: Arc<S>,
+    policy: RetentionPolicy,
+    interval: Duration,
+) -> RetentionTask
+where
+    S: GenerationStore + Send + Sync + 'static,
+{
+    let (stop, stop_rx) = sync_channel::<()>(0);
+    let thread = std::thread::Builder::new()
+        .name("bottomless-retention-timer".into())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to start bottomless retention runtime");
+            loop {
+                match stop_rx.recv_timeout(interval) {
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let result = runtime.block_on(prune_generations(
+                            store.as_ref(),
+                            &policy,
+                            SystemTime::now(),
+                        ));
+                        if let Err(e) = result {
+                            tracing::warn!("periodic generation GC failed: {e}");
+                        }
+                    }
+                }
+            }
+        })
+        .expect("failed to spawn bottomless retention timer thread");
+    RetentionTask {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct FakeStore {
+        generations: Vec<GenerationInfo>,
+        deleted: Mutex<Vec<Uuid>>,
+    }
+
+    #[async_trait::async_trait]
+    impl GenerationStore for FakeStore {
+        async fn list_generations(&self) -> anyhow::Result<Vec<GenerationInfo>> {
+            Ok(self.generations.clone())
+        }
+
+        async fn delete_generation(&self, generation: Uuid) -> anyhow::Result<()> {
+            self.deleted.lock().unwrap().push(generation);
+            Ok(())
+        }
+    }
+
+    fn generation_at(secs: u64) -> GenerationInfo {
+        GenerationInfo {
+            generation: Uuid::new_v4(),
+            created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_policy_prunes_nothing() {
+        let store = FakeStore {
+            generations: vec![generation_at(0), generation_at(100)],
+            deleted: Mutex::new(Vec::new()),
+        };
+        let pruned = prune_generations(&store, &RetentionPolicy::default(), SystemTime::now())
+            .await
+            .unwrap();
+        assert!(pruned.is_empty());
+        assert!(store.deleted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_generations_keeps_newest_n() {
+        let generations = vec![
+            generation_at(0),
+            generation_at(10),
+            generation_at(20),
+            generation_at(30),
+        ];
+        let kept = generations[2..].iter().map(|g| g.generation).collect::<Vec<_>>();
+        let store = FakeStore {
+            generations,
+            deleted: Mutex::new(Vec::new()),
+        };
+        let policy = RetentionPolicy {
+            max_generations: Some(2),
+            ..Default::default()
+        };
+        let pruned = prune_generations(&store, &policy, SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(pruned.len(), 2);
+        for generation in kept {
+            assert!(!pruned.contains(&generation));
+        }
+    }
+
+    #[tokio::test]
+    async fn max_generations_larger_than_stored_keeps_everything() {
+        let store = FakeStore {
+            generations: vec![generation_at(0), generation_at(10)],
+            deleted: Mutex::new(Vec::new()),
+        };
+        let policy = RetentionPolicy {
+            max_generations: Some(10),
+            ..Default::default()
+        };
+        let pruned = prune_generations(&store, &policy, SystemTime::now())
+            .await
+            .unwrap();
+        assert!(pruned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_age_prunes_only_whats_older_than_cutoff() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let store = FakeStore {
+            generations: vec![generation_at(0), generation_at(995)],
+            deleted: Mutex::new(Vec::new()),
+        };
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(100)),
+            ..Default::default()
+        };
+        let pruned = prune_generations(&store, &policy, now).await.unwrap();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0], store.generations[0].generation);
+    }
+
+    #[tokio::test]
+    async fn constraints_combine_conservatively() {
+        // max_generations alone would keep only the newest, but
+        // min_restorable_horizon must also hold, so it wins out and nothing
+        // gets pruned.
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let store = FakeStore {
+            generations: vec![generation_at(0), generation_at(999)],
+            deleted: Mutex::new(Vec::new()),
+        };
+        let policy = RetentionPolicy {
+            max_generations: Some(1),
+            min_restorable_horizon: Some(Duration::from_secs(2_000)),
+            ..Default::default()
+        };
+        let pruned = prune_generations(&store, &policy, now).await.unwrap();
+        assert!(pruned.is_empty());
+    }
+}
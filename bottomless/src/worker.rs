@@ -0,0 +1,421 @@
+use std::future::Future;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::time::Duration;
+
+use libsql_sys::ffi::{SQLITE_BUSY, SQLITE_IOERR_WRITE};
+use libsql_sys::wal::{Error, Result};
+use uuid::Uuid;
+
+use crate::replicator::Replicator;
+
+// Mirrors the checkpoint hook's previous hard-coded deadline for
+// `wait_until_committed` specifically: if the worker hasn't replied by then,
+// something downstream (S3, the network) is stuck and we'd rather tell
+// SQLite to retry than block a WAL hook forever. Bounds both sides of a
+// `call()` round trip: on the worker thread, `with_deadline` wraps the
+// `wait_until_committed` await itself, so a stuck S3 call is actually
+// cancelled and the worker loop moves on to the next queued command instead
+// of wedging forever (the worker processes one command at a time, so
+// without this every other connection's `insert_frames`/`checkpoint` would
+// eventually block on the command channel too); on the calling WAL-hook
+// thread, `call`'s `reply_rx.recv_timeout` bounds how long the hook waits
+// for that reply. This is NOT used for every command —
+// `new_generation`/`snapshot_main_db_file` ran unbounded in the baseline
+// code (a full snapshot upload can legitimately take far longer than a
+// second) and still do, via `ReplicatorHandle::call_blocking`.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Awaits `fut`, giving up after `timeout` and logging `context` (a
+/// description of what was being waited on, for the log line). Dropping the
+/// inner future on timeout cancels it, which is what actually frees the
+/// single-threaded worker loop to move on to the next command — unlike
+/// `call`'s `recv_timeout`, which only bounds the caller and does nothing
+/// for an await stuck on the worker thread itself.
+///
+/// Factored out of [`ReplicatorHandle::handle`] so the timeout behavior can
+/// be unit tested against a slow/never-resolving future, independent of a
+/// real [`Replicator`].
+async fn with_deadline<T>(timeout: Duration, context: &str, fut: impl Future<Output = T>) -> Result<T> {
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(value) => Ok(value),
+        Err(_) => {
+            tracing::error!("timed out waiting for {context}");
+            Err(Error::new(SQLITE_BUSY))
+        }
+    }
+}
+
+/// Commands a WAL hook can ask the replicator worker thread to perform on
+/// its behalf. Each carries the reply channel the hook blocks on.
+enum ReplicatorCommand {
+    RegisterFrames {
+        last_valid_frame: u32,
+        new_valid_frame_index: u32,
+        page_size: usize,
+        reply: SyncSender<Result<()>>,
+    },
+    WaitCommitted {
+        reply: SyncSender<Result<bool>>,
+    },
+    WaitFlushed {
+        reply: SyncSender<Result<()>>,
+    },
+    Snapshot {
+        reply: SyncSender<Result<()>>,
+    },
+    NewGeneration {
+        reply: SyncSender<()>,
+    },
+    PeekLastValidFrame {
+        reply: SyncSender<u32>,
+    },
+    Stats {
+        reply: SyncSender<ReplicatorStats>,
+    },
+}
+
+/// A point-in-time snapshot of the replicator's progress, used to derive the
+/// replication-lag metric without handing out direct access to the
+/// [`Replicator`] itself.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ReplicatorStats {
+    /// Last frame written to the local WAL.
+    pub(crate) local_frame: u32,
+    /// Last frame handed off to the replicator for upload.
+    pub(crate) known_frame: u32,
+    /// Id of the generation currently being written to, if one has started.
+    pub(crate) current_generation: Option<Uuid>,
+}
+
+/// A handle to the background thread that owns the [`Replicator`] and the
+/// tokio runtime it runs on.
+///
+/// This is the libsql equivalent of the "statement worker" sqlx moved its
+/// SQLite connections onto to stop segfaulting: instead of every WAL hook
+/// assuming a tokio runtime is already current on whatever thread SQLite
+/// happens to call it from, all replicator work is funneled through a single
+/// dedicated thread via a channel. Cloning the handle is just cloning a
+/// channel sender, so every connection sharing the same wrapper can send
+/// commands without contending on the replicator itself.
+#[derive(Clone)]
+pub(crate) struct ReplicatorHandle {
+    commands: SyncSender<ReplicatorCommand>,
+}
+
+impl ReplicatorHandle {
+    /// Spawns the worker thread, moving `replicator` onto it along with a
+    /// freshly built single-threaded tokio runtime that outlives every call
+    /// made through the returned handle.
+    pub(crate) fn spawn(replicator: Replicator) -> Self {
+        let (commands, rx) = sync_channel(32);
+        std::thread::Builder::new()
+            .name("bottomless-replicator".into())
+            .spawn(move || Self::run(replicator, rx))
+            .expect("failed to spawn bottomless replicator worker thread");
+        Self { commands }
+    }
+
+    fn run(mut replicator: Replicator, commands: Receiver<ReplicatorCommand>) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start bottomless replicator runtime");
+
+        for command in commands {
+            runtime.block_on(Self::handle(&mut replicator, command));
+        }
+        tracing::debug!("bottomless replicator worker thread shutting down");
+    }
+
+    async fn handle(replicator: &mut Replicator, command: ReplicatorCommand) {
+        match command {
+            ReplicatorCommand::RegisterFrames {
+                last_valid_frame,
+                new_valid_frame_index,
+                page_size,
+                reply,
+            } => {
+                match replicator.set_page_size(page_size) {
+                    Ok(()) => {
+                        replicator.register_last_valid_frame(last_valid_frame);
+                        replicator.submit_frames(new_valid_frame_index - last_valid_frame);
+                        let _ = reply.send(Ok(()));
+                    }
+                    Err(e) => {
+                        // A page-size change mid-stream means the backup
+                        // state can no longer be trusted; this was a
+                        // deliberate `std::process::abort()` before the
+                        // worker-thread refactor and stays one rather than
+                        // being downgraded to a recoverable WAL error.
+                        tracing::error!("fatal error during backup: {e}, exiting");
+                        std::process::abort();
+                    }
+                }
+            }
+            ReplicatorCommand::WaitCommitted { reply } => {
+                let last_known_frame = replicator.last_known_frame();
+                replicator.request_flush();
+                if last_known_frame == 0 {
+                    tracing::debug!("No committed changes in this generation, not snapshotting");
+                    replicator.skip_snapshot_for_current_generation();
+                    let _ = reply.send(Ok(false));
+                    return;
+                }
+                let result = match with_deadline(
+                    COMMAND_TIMEOUT,
+                    &format!("S3 replicator to confirm {last_known_frame} frames backup"),
+                    replicator.wait_until_committed(last_known_frame),
+                )
+                .await
+                {
+                    Ok(Ok(_)) => Ok(replicator.is_snapshotted().await),
+                    Ok(Err(e)) => {
+                        tracing::error!(
+                            "Failed to wait for S3 replicator to confirm {last_known_frame} frames backup: {e}"
+                        );
+                        Err(Error::new(SQLITE_IOERR_WRITE))
+                    }
+                    Err(timed_out) => Err(timed_out),
+                };
+                let _ = reply.send(result);
+            }
+            ReplicatorCommand::WaitFlushed { reply } => {
+                // Unlike `WaitCommitted`, this doesn't gate on the previous
+                // generation being snapshotted: a PASSIVE/FULL checkpoint
+                // isn't rolling the generation, it just needs the backfilled
+                // frames durably in object storage before it hands them to
+                // SQLite's own checkpoint.
+                let last_known_frame = replicator.last_known_frame();
+                replicator.request_flush();
+                let result = if last_known_frame == 0 {
+                    Ok(())
+                } else {
+                    match with_deadline(
+                        COMMAND_TIMEOUT,
+                        &format!("S3 replicator to confirm {last_known_frame} frames backup"),
+                        replicator.wait_until_committed(last_known_frame),
+                    )
+                    .await
+                    {
+                        Ok(Ok(_)) => Ok(()),
+                        Ok(Err(e)) => {
+                            tracing::error!(
+                                "Failed to wait for S3 replicator to confirm {last_known_frame} frames backup: {e}"
+                            );
+                            Err(Error::new(SQLITE_IOERR_WRITE))
+                        }
+                        Err(timed_out) => Err(timed_out),
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            ReplicatorCommand::Snapshot { reply } => {
+                let result = match replicator.snapshot_main_db_file(false).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to snapshot the main db file during checkpoint: {e}"
+                        );
+                        Err(Error::new(SQLITE_IOERR_WRITE))
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            ReplicatorCommand::NewGeneration { reply } => {
+                replicator.new_generation().await;
+                let _ = reply.send(());
+            }
+            ReplicatorCommand::PeekLastValidFrame { reply } => {
+                let _ = reply.send(replicator.peek_last_valid_frame());
+            }
+            ReplicatorCommand::Stats { reply } => {
+                let _ = reply.send(ReplicatorStats {
+                    local_frame: replicator.peek_last_valid_frame(),
+                    known_frame: replicator.last_known_frame(),
+                    current_generation: replicator.current_generation(),
+                });
+            }
+        }
+    }
+
+    /// Sends `build(reply)` to the worker and blocks the caller for at most
+    /// [`COMMAND_TIMEOUT`] waiting for the reply, translating channel
+    /// failures and timeouts into the `SQLITE_BUSY`/`SQLITE_IOERR_WRITE`
+    /// errors SQLite expects from a WAL hook instead of aborting the
+    /// process.
+    fn call<T>(&self, build: impl FnOnce(SyncSender<T>) -> ReplicatorCommand) -> Result<T> {
+        let (reply, reply_rx) = sync_channel(1);
+        self.commands
+            .send(build(reply))
+            .map_err(|_| Error::new(SQLITE_IOERR_WRITE))?;
+        match reply_rx.recv_timeout(COMMAND_TIMEOUT) {
+            Ok(value) => Ok(value),
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::error!("timed out waiting for the bottomless replicator worker");
+                Err(Error::new(SQLITE_BUSY))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(Error::new(SQLITE_IOERR_WRITE)),
+        }
+    }
+
+    /// Like [`Self::call`], but blocks without a deadline. Reserved for
+    /// commands whose baseline behavior was an unbounded `block_on` — unlike
+    /// `call`, a slow reply here never makes the hook give up early while
+    /// the worker keeps making progress on it in the background.
+    fn call_blocking<T>(&self, build: impl FnOnce(SyncSender<T>) -> ReplicatorCommand) -> Result<T> {
+        let (reply, reply_rx) = sync_channel(1);
+        self.commands
+            .send(build(reply))
+            .map_err(|_| Error::new(SQLITE_IOERR_WRITE))?;
+        reply_rx.recv().map_err(|_| Error::new(SQLITE_IOERR_WRITE))
+    }
+
+    pub(crate) fn register_frames(
+        &self,
+        last_valid_frame: u32,
+        new_valid_frame_index: u32,
+        page_size: usize,
+    ) -> Result<()> {
+        self.call(|reply| ReplicatorCommand::RegisterFrames {
+            last_valid_frame,
+            new_valid_frame_index,
+            page_size,
+            reply,
+        })?
+    }
+
+    /// Flushes and waits for everything up to the current `last_known_frame`
+    /// to be confirmed committed to object storage, returning whether the
+    /// previous generation has been snapshotted. Returns `false` without
+    /// waiting when nothing has been committed in this generation.
+    pub(crate) fn wait_committed(&self) -> Result<bool> {
+        self.call(|reply| ReplicatorCommand::WaitCommitted { reply })?
+    }
+
+    /// Flushes and waits for everything up to the current `last_known_frame`
+    /// to be confirmed committed to object storage, without checking
+    /// whether the previous generation was snapshotted. Used by PASSIVE/FULL
+    /// checkpoints, which don't roll the generation.
+    pub(crate) fn wait_flushed(&self) -> Result<()> {
+        self.call(|reply| ReplicatorCommand::WaitFlushed { reply })?
+    }
+
+    /// Unbounded, unlike [`Self::call`]: a full snapshot upload to S3 can
+    /// legitimately run well past `COMMAND_TIMEOUT` for any non-trivial
+    /// database, exactly as it did when this ran inline via `block_on`.
+    pub(crate) fn snapshot(&self) -> Result<()> {
+        self.call_blocking(|reply| ReplicatorCommand::Snapshot { reply })?
+    }
+
+    /// Unbounded, same rationale as [`Self::snapshot`].
+    pub(crate) fn new_generation(&self) -> Result<()> {
+        self.call_blocking(|reply| ReplicatorCommand::NewGeneration { reply })
+    }
+
+    pub(crate) fn peek_last_valid_frame(&self) -> Result<u32> {
+        self.call(|reply| ReplicatorCommand::PeekLastValidFrame { reply })
+    }
+
+    /// Returns a snapshot of the replicator's progress, used to compute the
+    /// replication-lag gauge.
+    pub(crate) fn stats(&self) -> Result<ReplicatorStats> {
+        self.call(|reply| ReplicatorCommand::Stats { reply })
+    }
+
+    /// Runs the same wait/new-generation/snapshot sequence a TRUNCATE
+    /// checkpoint does, skipping it when there's nothing committed yet.
+    /// Used by the periodic snapshot timer so a writer that never
+    /// checkpoints still gets bounded replication lag.
+    pub(crate) fn snapshot_if_due(&self) -> Result<()> {
+        if self.wait_committed()? {
+            self.new_generation()?;
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Spawns a thread that calls [`Self::snapshot_if_due`] every `interval`
+    /// until the returned [`SnapshotTask`] is dropped. Because every command
+    /// is funneled through the same worker thread as WAL hook commands, this
+    /// can never race a checkpoint's own snapshot.
+    pub(crate) fn spawn_periodic_snapshot(&self, interval: Duration) -> SnapshotTask {
+        let worker = self.clone();
+        let (stop, stop_rx) = sync_channel::<()>(0);
+        let thread = std::thread::Builder::new()
+            .name("bottomless-snapshot-timer".into())
+            .spawn(move || loop {
+                match stop_rx.recv_timeout(interval) {
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = worker.snapshot_if_due() {
+                            tracing::warn!("periodic bottomless snapshot failed: {e}");
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn bottomless snapshot timer thread");
+        SnapshotTask {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle to the background periodic-snapshot timer. Stops the timer and
+/// joins its thread when dropped.
+pub(crate) struct SnapshotTask {
+    stop: SyncSender<()>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for SnapshotTask {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DEADLINE: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn with_deadline_returns_busy_instead_of_waiting_forever() {
+        let result = with_deadline(TEST_DEADLINE, "a test", std::future::pending::<()>()).await;
+        assert!(
+            result.is_err(),
+            "a never-resolving future must time out, not hang forever"
+        );
+    }
+
+    #[tokio::test]
+    async fn with_deadline_passes_through_a_fast_future() {
+        let result = with_deadline(TEST_DEADLINE, "a test", async { 42 }).await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    // Regression test for the bug a missing deadline on `wait_until_committed`
+    // caused: a single stuck call must not wedge the worker loop. With a real
+    // `Replicator` unavailable here, this exercises the same guarantee at the
+    // level `handle()` relies on — a command that never resolves still lets
+    // the next one run, in bounded wall-clock time.
+    #[tokio::test]
+    async fn a_stuck_command_does_not_block_the_next_one() {
+        let started = std::time::Instant::now();
+
+        let stuck = with_deadline(TEST_DEADLINE, "a stuck command", std::future::pending::<()>());
+        assert!(stuck.await.is_err());
+
+        let next = with_deadline(TEST_DEADLINE, "the next command", async { "done" });
+        assert_eq!(next.await.unwrap(), "done");
+
+        assert!(
+            started.elapsed() < TEST_DEADLINE * 4,
+            "a stuck command must not delay the next one beyond its own deadline"
+        );
+    }
+}